@@ -0,0 +1,26 @@
+include!("src/modification_types.rs");
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    println!("cargo:rerun-if-changed=data/modifications.csv");
+
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR not set.");
+    let csv_path = Path::new(&manifest_dir).join("data/modifications.csv");
+    let mut reader = csv::Reader::from_path(&csv_path)
+        .unwrap_or_else(|e| panic!("Could not open {}: {e}", csv_path.display()));
+
+    let mut table: HashMap<String, ModificationEntry> = HashMap::new();
+    for record in reader.deserialize::<ModificationEntry>() {
+        let entry = record.expect("Could not parse a row of data/modifications.csv.");
+        table.insert(entry.name.clone(), entry);
+    }
+
+    let encoded = bincode::serialize(&table).expect("Could not serialize modification ontology.");
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR not set.");
+    fs::write(Path::new(&out_dir).join("modifications.bin"), encoded)
+        .expect("Could not write modifications.bin.");
+}
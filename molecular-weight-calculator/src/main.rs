@@ -10,13 +10,49 @@ Options:
     -F, --file => Input file (read based on a .fasta, or .csv file (column must be named "formula")).
     -h, --help => Show this
     -f, --format => Show help for file formatting.
+    --isotopes => Print the theoretical isotope distribution instead of a single mass.
 */
 
-use clap::{arg, error::ErrorKind, ArgGroup, Args, Command};
+use clap::{arg, error::ErrorKind, Arg, ArgGroup, Args, Command};
 use colored::Colorize;
 use regex::Regex;
 use std::{env, path::PathBuf};
-use std::num::ParseFloatError;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::sync::OnceLock;
+
+mod modification_types;
+use modification_types::ModificationEntry;
+
+/// Only peaks at or above this fraction of the running maximum probability
+/// survive a convolution; everything else is discarded so the distribution
+/// can't blow up combinatorially as elements are combined.
+const ISOTOPE_PRUNE_THRESHOLD: f64 = 1e-6;
+/// Peaks within this many Da of each other are centroided into one peak.
+const ISOTOPE_MERGE_TOLERANCE: f64 = 0.002;
+/// Only peaks at or above this fraction of the base (most intense) peak are
+/// printed in `--isotopes` mode.
+const ISOTOPE_DISPLAY_CUTOFF: f64 = 0.01;
+
+/// Average atomic weights used to build the small-molecule neutral-loss
+/// masses (water, ammonia, carbon monoxide) needed by `--fragments`.
+const CARBON_MASS: f32 = 12.011;
+const OXYGEN_MASS: f32 = 15.9994;
+const NITROGEN_MASS: f32 = 14.0067;
+const HYDROGEN_MASS: f32 = 1.00794;
+
+/// `amino.csv` stores each residue as its free (unbonded) amino acid
+/// formula, so every peptide bond formed between two residues loses one of
+/// these. Shared by the precursor mass (`parse_protein_formula`) and the
+/// fragment-ion ladder (`peptide_residue_masses`) so the two stay
+/// consistent with each other.
+const WATER_MASS: f32 = 2.0 * HYDROGEN_MASS + OXYGEN_MASS;
+
+/// An element -> count composition map, as produced by
+/// `parse_molecular_formula` and consumed directly by `mass_from_formula`,
+/// `isotope_distribution`, and the protein/nucleic-acid residue parsers.
+type Composition = HashMap<String, i64>;
 
 #[derive(Debug, serde::Deserialize, Clone)]
 struct ElementRow {
@@ -51,6 +87,13 @@ struct NucleicAcidRow {
     // charge: i32,
 }
 
+#[derive(Debug, serde::Deserialize, Clone)]
+struct IsotopeRow {
+    element: String,
+    isotope_mass: String,
+    abundance: String,
+}
+
 #[derive(Debug, Args)]
 #[command(author = "Trevor Fox, voxelmc2@student.ubc.ca", version = "1.0.0", about, long_about = None)]
 struct Arguments {
@@ -80,6 +123,35 @@ fn main() {
         .arg(arg!(-p --protein "Notify the calculator to parse the formula as single-letter IUPAC amino acids."))
         .arg(arg!(-f --format "Shows information about the required file format for file-based input."))
         .arg(arg!(-F --file <FILE> "Specify a file for the calculator to read the formula from."))
+        .arg(arg!(--isotopes "Print the theoretical isotope distribution (top peaks) instead of a single mass."))
+        .arg(
+            Arg::new("min_intensity")
+                .long("min-intensity")
+                .value_name("FRACTION")
+                .help("With --isotopes, only print peaks at or above this fraction of the base peak's intensity. Defaults to 0.01 (1%)."),
+        )
+        .arg(arg!(--fragments "Print the b/y (and optionally a/c/x/z) fragment-ion ladder for a peptide given via --protein."))
+        .arg(
+            Arg::new("ions")
+                .long("ions")
+                .value_name("SERIES")
+                .value_delimiter(',')
+                .help("Fragment ion series to include with --fragments: a, b, c, x, y, z. Defaults to b,y."),
+        )
+        .arg(
+            Arg::new("charge")
+                .long("charge")
+                .value_name("Z")
+                .value_delimiter(',')
+                .help("Charge state(s) to report m/z for, e.g. --charge 1,2. Composes with the scalar and --isotopes outputs, which otherwise print the neutral mass (or, for a formula with an embedded charge such as SO4^2-, that formula's mass divided by its charge count)."),
+        )
+        .arg(
+            Arg::new("adduct")
+                .long("adduct")
+                .value_name("ADDUCT")
+                .value_delimiter(',')
+                .help("Adduct(s) per --charge value: H, Na, K, NH4, or -H for deprotonation. When --charge is given without --adduct, defaults to [M+H]+ for peptides and [M-H]- for nucleotides."),
+        )
         .arg(arg!(--debug "Launch in verbose debugging mode."))
         .arg(arg!(-s --silent "Print only the numeric mass."))
         .group(
@@ -93,8 +165,47 @@ fn main() {
 
     let cli = Arguments::augment_args(cli);
 
-    let h_mass: f32 = 1.00794;
     let matches = cli.get_matches();
+
+    let is_average = matches.get_flag("average");
+    let is_debug = matches.get_flag("debug");
+    let is_protein = matches.get_flag("protein");
+    let is_dna = matches.get_flag("dna");
+    let is_rna = matches.get_flag("rna");
+    let is_silent = matches.get_flag("silent");
+    let is_isotopes = matches.get_flag("isotopes");
+    let min_intensity: f64 = matches
+        .get_one::<String>("min_intensity")
+        .map(|value| value.parse().expect("Could not parse --min-intensity as a float."))
+        .unwrap_or(ISOTOPE_DISPLAY_CUTOFF);
+    let is_fragments = matches.get_flag("fragments");
+    let ion_series: Vec<String> = matches
+        .get_many::<String>("ions")
+        .map(|values| values.cloned().collect())
+        .unwrap_or_else(|| vec!["b".to_string(), "y".to_string()]);
+
+    let charges: Vec<i32> = matches
+        .get_many::<String>("charge")
+        .map(|values| {
+            values
+                .map(|value| value.parse::<i32>().expect("Could not parse --charge value as an integer."))
+                .collect()
+        })
+        .unwrap_or_default();
+    let default_adduct = if is_protein {
+        "H"
+    } else if is_dna || is_rna {
+        "-H"
+    } else {
+        "H"
+    };
+    let adducts = resolve_adducts(&matches, &charges, default_adduct);
+
+    if let Some(file_path) = matches.get_one::<String>("file") {
+        process_file(file_path, is_protein, is_dna, is_rna, is_average, is_debug, is_silent);
+        return;
+    }
+
     let molecule_string = match matches.get_raw("formula") {
         Some(mut raw) => raw
             .next()
@@ -109,204 +220,842 @@ fn main() {
         }
     };
 
-    let is_average = matches.get_flag("average");
-    let is_debug = matches.get_flag("debug");
-
-    if !matches.get_flag("silent") {
+    if !is_silent {
         println!(
             "Calculating {} mass for: {}",
-            if matches.get_flag("average") {
-                "average"
-            } else {
-                "exact"
-            },
+            if is_average { "average" } else { "exact" },
             molecule_string.bright_yellow().bold()
         );
     }
 
-    if matches.get_flag("protein") {
-        let formula_vec = parse_protein_formula(molecule_string);
-        let mut number_of_hydrogens = -2.0;
-        let _ = formula_vec.iter().for_each(|val| {
-            if val.contains("H") {
-                let reg = Regex::new(r"(\d+)").expect("RegEx parsing error.");
-                let matched = reg
-                    .find(&val).expect("").as_str().to_owned();
-                let parsed: Result<f32, ParseFloatError> = matched.parse::<f32>();
-                number_of_hydrogens += parsed.expect("Could not parse # of hydrogens as a float.");
+    if is_isotopes {
+        let parsed = parse_sequence(molecule_string, is_protein, is_dna, is_rna, is_average);
+        let composition = expand_composition(&parsed.composition).unwrap_or_else(|reason| panic!("{}", reason));
+        let distribution = shift_distribution_mass(isotope_distribution(&composition), parsed.numeric_delta as f64);
+
+        if charges.is_empty() {
+            if let Some(formula_charge) = parsed.charge {
+                // Same bare-charged-species assumption as the scalar path
+                // below: the formula already represents the complete ion
+                // (e.g. `SO4^2-`), so there's no separate adduct to add or
+                // subtract -- just divide every peak's mass by the charge
+                // count.
+                println!("m/z (z = {:+}):", formula_charge);
+                let z = formula_charge.unsigned_abs().max(1) as f64;
+                let mz_distribution: IsotopeDistribution = distribution
+                    .iter()
+                    .map(|&(mass, probability)| (mass / z, probability))
+                    .collect();
+                print_isotope_distribution(&mz_distribution, min_intensity);
+            } else {
+                print_isotope_distribution(&distribution, min_intensity);
             }
-        });
-            // .collect::<Vec<&String>>().len() as i32;
-        let mass_to_subtract: f32 = number_of_hydrogens * h_mass;
-        let output = mass_from_formula(formula_vec, is_debug, is_average) - mass_to_subtract;
-        println!("{:?}", output);
+        } else {
+            for (charge, adduct) in charges.iter().zip(adducts.iter()) {
+                println!("Charge {} (adduct {}):", charge, adduct_label(adduct));
+                let mz_distribution: IsotopeDistribution = distribution
+                    .iter()
+                    .map(|&(mass, probability)| (mz_for_charge(mass as f32, *charge, adduct) as f64, probability))
+                    .collect();
+                print_isotope_distribution(&mz_distribution, min_intensity);
+            }
+        }
         return;
     }
 
-    if matches.get_flag("dna") {
-        let formula_vec = parse_nucleic_formula(molecule_string, false, is_average);
-        let output = mass_from_formula(formula_vec, is_debug, is_average);
-        println!("{:?}", output);
+    if is_fragments {
+        if !is_protein {
+            eprintln!("--fragments requires --protein.");
+            std::process::exit(1);
+        }
+        let charge = charges.first().copied().unwrap_or(1);
+        print_fragment_ions(&molecule_string, is_average, is_debug, charge, &ion_series);
         return;
     }
 
-    if matches.get_flag("rna") {
-        let formula_vec = parse_nucleic_formula(molecule_string, true, is_average);
-        let output = mass_from_formula(formula_vec, is_debug, is_average);
-        println!("{:?}", output);
-        return;
+    let parsed = parse_sequence(molecule_string, is_protein, is_dna, is_rna, is_average);
+    let output = mass_from_formula(&parsed.composition, is_debug, is_average).unwrap_or_else(|reason| panic!("{}", reason))
+        + parsed.numeric_delta;
+
+    if charges.is_empty() {
+        if let Some(formula_charge) = parsed.charge {
+            // Unlike `mz_for_charge` below, there's no separate adduct term
+            // here: a formula-embedded charge (e.g. the ^2- in SO4^2-) means
+            // the formula already represents the complete charged species,
+            // not a neutral parent that still needs an adduct added, so m/z
+            // is just the formula's mass divided by the charge count.
+            let mz = output / (formula_charge.unsigned_abs().max(1)) as f32;
+            println!("m/z (z = {:+}): {:?}", formula_charge, mz);
+        } else {
+            println!("{:?}", output);
+        }
+    } else {
+        println!("{:>14} {:>10} {:>14}", "z", "Adduct", "m/z");
+        for (charge, adduct) in charges.iter().zip(adducts.iter()) {
+            let mz = mz_for_charge(output, *charge, adduct);
+            println!("{:>14} {:>10} {:>14.4}", charge, adduct_label(adduct), mz);
+        }
+    }
+}
+
+/// Resolves the `--adduct` list against the `--charge` list: one adduct
+/// applies to every charge, a matching-length list pairs up positionally,
+/// and an empty list falls back to `default_adduct` for every charge.
+fn resolve_adducts(matches: &clap::ArgMatches, charges: &[i32], default_adduct: &str) -> Vec<String> {
+    let provided: Vec<String> = matches
+        .get_many::<String>("adduct")
+        .map(|values| values.cloned().collect())
+        .unwrap_or_default();
+
+    if provided.is_empty() {
+        return vec![default_adduct.to_string(); charges.len()];
     }
 
-    let formula_vec: Vec<String> = parse_molecular_formula(molecule_string);
+    if provided.len() == 1 {
+        return vec![provided[0].clone(); charges.len()];
+    }
 
-    let output = mass_from_formula(
-        formula_vec,
-        matches.get_flag("debug"),
-        matches.get_flag("average"),
+    assert_eq!(
+        provided.len(),
+        charges.len(),
+        "--adduct must specify exactly one adduct, or one per --charge value."
     );
-    println!("{:?}", output);
+    provided
 }
 
-/// CH3 -> ["C", "H3"]
-fn parse_molecular_formula<'a>(formula: String) -> Vec<String> {
-    let reg = Regex::new(r"[A-Za-z][a-z]{0,2}\d*|(<!\([^)])\(.*\)\d+(![^(]*\))")
-        .expect("RegEx parsing error.");
-    let binding = reg.to_owned();
-    let out = binding.find_iter(formula.as_str());
+/// Mass and polarity (true = added to the ion, false = a deprotonation)
+/// of a supported adduct.
+fn adduct_mass(adduct: &str) -> (f32, bool) {
+    match adduct {
+        "H" => (1.00794, true),
+        "Na" => (22.98977, true),
+        "K" => (39.0983, true),
+        "NH4" => (18.03846, true),
+        "-H" => (1.00794, false),
+        other => panic!("Unknown adduct {:?}. Supported adducts: H, Na, K, NH4, -H.", other),
+    }
+}
 
-    let mut out_vec: Vec<String> = vec![];
-    out.for_each(|val| {
-        let in_vec: Vec<String> = vec![val.as_str().to_owned()];
-        out_vec.append(in_vec.to_vec().as_mut());
-    });
+/// Formats an adduct for display, e.g. "H" -> "+H", "-H" -> "-H".
+fn adduct_label(adduct: &str) -> String {
+    if adduct.starts_with('-') {
+        adduct.to_string()
+    } else {
+        format!("+{}", adduct)
+    }
+}
 
-    out_vec
+/// Converts a neutral mass `M` to the observed m/z at the given charge
+/// state: `(M + |z|*adduct_mass) / |z|` for positive adducts, or
+/// `(M - |z|*adduct_mass) / |z|` for deprotonation. `charge`'s sign only
+/// ever picks the polarity label printed alongside it (see `print` call
+/// sites); the adduct math itself always uses the charge count, never its
+/// sign, so e.g. `--charge -1` with the "-H" adduct still yields `M - H`
+/// rather than flipping to `M + H`.
+fn mz_for_charge(neutral_mass: f32, charge: i32, adduct: &str) -> f32 {
+    let (mass, is_positive) = adduct_mass(adduct);
+    let z = charge.unsigned_abs() as f32;
+    if is_positive {
+        (neutral_mass + z * mass) / z
+    } else {
+        (neutral_mass - z * mass) / z
+    }
 }
 
-fn mass_from_formula<'ass>(parsed_formula: Vec<String>, is_debug: bool, is_average: bool) -> f32 {
-    let mut aggregate_mass: f32 = 0.0;
+/// A parsed formula/sequence: the element composition to sum via
+/// `mass_from_formula`/`isotope_distribution`, plus any additional mass delta
+/// (e.g. from a numeric-delta modification, or the peptide-bond water-loss
+/// correction) that doesn't fit into the composition model, plus any charge
+/// embedded in the formula itself (e.g. the `^2-` in `SO4^2-`).
+struct ParsedSequence {
+    composition: Composition,
+    numeric_delta: f32,
+    charge: Option<i32>,
+}
 
-    for atom in parsed_formula {
-        let reg = Regex::new(r"(\D+)|(\d+)").expect("RegEx parsing error.");
-        let matches: Vec<String> = reg
-            .find_iter(&atom)
-            .map(|val| val.as_str().to_owned())
-            .collect();
+/// Parses a formula/sequence, dispatching to the protein/DNA/RNA residue
+/// parsers or the plain molecular formula parser depending on which molecule
+/// flag is set.
+fn parse_sequence(
+    sequence: String,
+    is_protein: bool,
+    is_dna: bool,
+    is_rna: bool,
+    is_average: bool,
+) -> ParsedSequence {
+    if is_protein {
+        return parse_protein_formula(&sequence, is_average);
+    }
 
-        let current_exe_res = env::current_exe();
-        let mut current_exe_path: PathBuf =
-            current_exe_res.expect("Could not read executable path.");
-        current_exe_path.pop();
+    if is_dna {
+        return ParsedSequence {
+            composition: parse_nucleic_formula(&sequence, false, is_average),
+            numeric_delta: 0.0,
+            charge: None,
+        };
+    }
+
+    if is_rna {
+        return ParsedSequence {
+            composition: parse_nucleic_formula(&sequence, true, is_average),
+            numeric_delta: 0.0,
+            charge: None,
+        };
+    }
+
+    let (composition, charge) =
+        parse_molecular_formula(&sequence).unwrap_or_else(|reason| panic!("{}", reason));
+    ParsedSequence { composition, numeric_delta: 0.0, charge }
+}
+
+/// Computes the mass for a single formula/sequence, dispatching to the
+/// protein/DNA/RNA residue parsers or the plain molecular formula parser
+/// depending on which molecule flag is set.
+fn mass_for_sequence(
+    sequence: String,
+    is_protein: bool,
+    is_dna: bool,
+    is_rna: bool,
+    is_average: bool,
+    is_debug: bool,
+) -> f32 {
+    let parsed = parse_sequence(sequence, is_protein, is_dna, is_rna, is_average);
+    mass_from_formula(&parsed.composition, is_debug, is_average).unwrap_or_else(|reason| panic!("{}", reason))
+        + parsed.numeric_delta
+}
 
-        let elements_csv_path: PathBuf = if is_average {
-            current_exe_path.join("../../data/elements.csv")
+/// Batch mode for `-F/--file`: dispatches on the file extension to either
+/// the FASTA record-by-record reader or the CSV `formula` column reader.
+fn process_file(
+    file_path: &str,
+    is_protein: bool,
+    is_dna: bool,
+    is_rna: bool,
+    is_average: bool,
+    is_debug: bool,
+    is_silent: bool,
+) {
+    let path = PathBuf::from(file_path);
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    match extension.as_str() {
+        "fasta" | "fa" => process_fasta_file(&path, is_protein, is_dna, is_rna, is_average, is_debug, is_silent),
+        "csv" => process_csv_file(&path, is_average, is_debug, is_silent),
+        other => {
+            eprintln!("Unsupported file extension for \"--file\": {:?}", other);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Streams a FASTA file record-by-record (rather than loading it all into
+/// memory) and prints one mass per record.
+fn process_fasta_file(
+    path: &PathBuf,
+    is_protein: bool,
+    is_dna: bool,
+    is_rna: bool,
+    is_average: bool,
+    is_debug: bool,
+    is_silent: bool,
+) {
+    let file = File::open(path).expect("Could not open FASTA file.");
+    let reader = BufReader::new(file);
+
+    let emit = |id: &str, sequence: &str, is_silent: bool| {
+        if sequence.is_empty() {
+            return;
+        }
+        let mass = mass_for_sequence(sequence.to_owned(), is_protein, is_dna, is_rna, is_average, is_debug);
+        if is_silent {
+            println!("{:?}", mass);
         } else {
-            current_exe_path.join("../../data/absolute.csv")
+            println!("{}: {:?}", id, mass);
         }
-        .canonicalize()
-        .expect("Canonicalization of executable path failed.");
+    };
 
-        // Try mapping to clones to remake each time, instead of new reader.
-        let elements_csv_stream = csv::Reader::from_path(elements_csv_path);
-        let mut elements_deserialize_binding = elements_csv_stream.unwrap();
-        let mut elements_csv_deserialized =
-            elements_deserialize_binding.deserialize::<ElementRow>();
-
-        let abbr_csv_path: PathBuf = current_exe_path
-            .join("../../data/abbreviations.csv")
-            .canonicalize()
-            .expect("Canonicalization of executable path failed.");
-        let abbr_csv_stream = csv::Reader::from_path(abbr_csv_path);
-        let mut abbr_deserialize_binding = abbr_csv_stream.unwrap();
-        let mut abbr_csv_deserialized = abbr_deserialize_binding.deserialize::<AbbreviationRow>();
-
-        let _expanded_abbr = match abbr_csv_deserialized.find(|row| {
-            row.as_ref()
-                .expect("Could not get AbbreviationRow")
-                .abbreviation
-                == matches[0]
-        }) {
-            Some(res) => match res {
-                Ok(found) => {
-                    let parsed = parse_molecular_formula(found.formula);
-                    if is_debug {
-                        println!("Expanded Abbreviation: {:?}", parsed)
-                    };
-                    let weight = mass_from_formula(parsed, is_debug, is_average);
-
-                    if matches.len().eq(&1) {
-                        aggregate_mass += weight;
-                    } else if matches.len().eq(&2) {
-                        let element_count = matches[1]
-                            .parse::<f32>()
-                            .expect("Could not parse element count into a float32.");
-                        aggregate_mass += weight * element_count;
-                    }
-                    continue;
-                }
-                Err(_e) => (),
-            },
-            None => (),
+    let mut current_id: Option<String> = None;
+    let mut current_sequence = String::new();
+
+    for line in reader.lines() {
+        let line = line.expect("Could not read line from FASTA file.");
+        if let Some(header) = line.strip_prefix('>') {
+            if let Some(id) = current_id.take() {
+                emit(&id, &current_sequence, is_silent);
+            }
+            current_id = Some(header.trim().to_owned());
+            current_sequence.clear();
+        } else {
+            current_sequence.push_str(line.trim());
+        }
+    }
+
+    if let Some(id) = current_id.take() {
+        emit(&id, &current_sequence, is_silent);
+    }
+}
+
+/// Streams a CSV file row-by-row, calculating the molecular mass of the
+/// formula found in the required "formula" column. Uses the `csv` crate
+/// (not `polars`, which this binary doesn't depend on) to stay consistent
+/// with every other CSV read in this file (elements, amino acids, nucleic
+/// acids, abbreviations, isotopes).
+fn process_csv_file(path: &PathBuf, is_average: bool, is_debug: bool, is_silent: bool) {
+    let mut reader = csv::Reader::from_path(path).expect("Could not open CSV file.");
+    let headers = reader.headers().expect("Could not read CSV headers.").clone();
+    let formula_index = headers
+        .iter()
+        .position(|header| header == "formula")
+        .expect("CSV file must contain a \"formula\" column.");
+    let id_index = headers.iter().position(|header| header == "id");
+
+    for (row_index, record) in reader.records().enumerate() {
+        let record = record.expect("Could not read CSV record.");
+        let formula = record
+            .get(formula_index)
+            .expect("Missing formula value in row.")
+            .to_owned();
+        let id = id_index
+            .and_then(|idx| record.get(idx))
+            .map(|id| id.to_owned())
+            .unwrap_or_else(|| (row_index + 1).to_string());
+
+        // A malformed formula is bad input data, not a programmer error like
+        // the trusted element/amino-acid CSVs read elsewhere in this file, so
+        // one bad row shouldn't abort the whole batch. `parse_molecular_formula`
+        // and `mass_from_formula` both return `Result` precisely so this loop
+        // can skip-and-warn on either kind of failure (an unparsable formula,
+        // or a valid-looking one with an unknown element/abbreviation) instead
+        // of the whole run.
+        let mass = parse_molecular_formula(&formula)
+            .and_then(|(composition, _charge)| mass_from_formula(&composition, is_debug, is_average));
+        let mass = match mass {
+            Ok(mass) => mass,
+            Err(reason) => {
+                eprintln!("Skipping row {:?}: {}", id, reason);
+                continue;
+            }
         };
 
-        if is_debug {
-            println!("Element: {:?}", matches[0]);
-            println!("Matches: {:?}", matches);
-            println!("Matches Len: {:?}", matches.len());
-            println!("Aggregated Mass: {:?}", aggregate_mass);
+        if is_silent {
+            println!("{:?}", mass);
+        } else {
+            println!("{}: {:?}", id, mass);
+        }
+    }
+}
+
+/// Recursive-descent parser for molecular formulas: handles nested `(...)`
+/// groups with subscript multipliers (`Ca(OH)2`, `Fe(C5H5)2`), `\u{b7}`/`.`
+/// hydrate separators with a leading multiplier (`CuSO4\u{b7}5H2O`), and a
+/// trailing `^n\u{b1}` charge (`SO4^2-`). Returns the element -> count
+/// composition of the formula (abbreviations are left as their own
+/// single-letter-run keys; `mass_from_formula`/`expand_composition` resolve
+/// those against `data/abbreviations.csv`) and, if present, the charge.
+///
+/// Returns `Err` instead of panicking on malformed input (an unexpected
+/// character, unbalanced parentheses, or a dangling `^` with no `+`/`-`), so
+/// batch callers like `process_csv_file` can skip a bad row instead of
+/// aborting.
+///
+/// `CH3` -> `{"C": 1, "H": 3}`
+fn parse_molecular_formula(formula: &str) -> Result<(Composition, Option<i32>), String> {
+    let chars: Vec<char> = formula.chars().collect();
+    let mut cursor = 0;
+    let mut composition = parse_formula_terms(&chars, &mut cursor)?;
+
+    while matches!(chars.get(cursor), Some('\u{b7}') | Some('.')) {
+        cursor += 1;
+        let multiplier = parse_integer(&chars, &mut cursor).unwrap_or(1);
+        let hydrate = parse_formula_terms(&chars, &mut cursor)?;
+        merge_composition(&mut composition, hydrate, multiplier);
+    }
+
+    let charge = parse_charge(&chars, &mut cursor)?;
+
+    if cursor != chars.len() {
+        return Err(format!("Unexpected character {:?} in formula {:?}.", chars[cursor], formula));
+    }
+
+    Ok((composition, charge))
+}
+
+/// Parses a run of element symbols and `(...)` groups, stopping (without
+/// consuming) at a hydrate separator, a charge marker, or an unmatched `)`.
+fn parse_formula_terms(chars: &[char], cursor: &mut usize) -> Result<Composition, String> {
+    let mut composition: Composition = HashMap::new();
+
+    while let Some(&c) = chars.get(*cursor) {
+        match c {
+            '(' => {
+                *cursor += 1;
+                let inner = parse_formula_terms(chars, cursor)?;
+                if chars.get(*cursor) != Some(&')') {
+                    return Err("Unbalanced \"(\" in formula.".to_string());
+                }
+                *cursor += 1;
+                let multiplier = parse_integer(chars, cursor).unwrap_or(1);
+                merge_composition(&mut composition, inner, multiplier);
+            }
+            ')' | '\u{b7}' | '.' | '^' => break,
+            ' ' | '\t' => *cursor += 1,
+            c if c.is_ascii_uppercase() => {
+                let symbol = parse_element_symbol(chars, cursor);
+                let count = parse_integer(chars, cursor).unwrap_or(1);
+                *composition.entry(symbol).or_insert(0) += count;
+            }
+            other => return Err(format!("Unexpected character {:?} in formula.", other)),
         }
+    }
+
+    Ok(composition)
+}
+
+/// An element symbol: one uppercase letter followed by up to two lowercase
+/// letters (also how `data/abbreviations.csv` keys are shaped).
+fn parse_element_symbol(chars: &[char], cursor: &mut usize) -> String {
+    let start = *cursor;
+    *cursor += 1;
+    while *cursor - start < 3 && matches!(chars.get(*cursor), Some(c) if c.is_ascii_lowercase()) {
+        *cursor += 1;
+    }
+    chars[start..*cursor].iter().collect()
+}
+
+/// A run of ASCII digits, or `None` if the cursor isn't on one.
+fn parse_integer(chars: &[char], cursor: &mut usize) -> Option<i64> {
+    let start = *cursor;
+    while matches!(chars.get(*cursor), Some(c) if c.is_ascii_digit()) {
+        *cursor += 1;
+    }
+    if *cursor == start {
+        return None;
+    }
+    Some(
+        chars[start..*cursor]
+            .iter()
+            .collect::<String>()
+            .parse()
+            .expect("Could not parse an integer in formula."),
+    )
+}
 
-        let element: Option<Result<ElementRow, csv::Error>> =
-            elements_csv_deserialized.find(|val| val.as_ref().unwrap().element == matches[0]);
-
-        let element_weight = element
-            .expect("1")
-            .expect("2")
-            .weight
-            .parse::<f32>()
-            .expect("Could not parse element mass from dataset as a float32.");
-        if matches.len().eq(&1) {
-            aggregate_mass += element_weight;
-        } else if matches.len().eq(&2) {
-            let element_count = matches[1]
+/// An optional trailing `^n+`/`^n-` charge, e.g. `SO4^2-` -> `Some(-2)`.
+fn parse_charge(chars: &[char], cursor: &mut usize) -> Result<Option<i32>, String> {
+    if chars.get(*cursor) != Some(&'^') {
+        return Ok(None);
+    }
+    *cursor += 1;
+    let magnitude = parse_integer(chars, cursor).unwrap_or(1) as i32;
+    match chars.get(*cursor) {
+        Some('+') => {
+            *cursor += 1;
+            Ok(Some(magnitude))
+        }
+        Some('-') => {
+            *cursor += 1;
+            Ok(Some(-magnitude))
+        }
+        other => Err(format!("Expected \"+\" or \"-\" after \"^\" in charge notation, found {:?}.", other)),
+    }
+}
+
+/// Adds every `(element, count * multiplier)` pair from `from` into `into`,
+/// used for `(...)` groups, hydrates, and abbreviation expansion alike.
+fn merge_composition(into: &mut Composition, from: Composition, multiplier: i64) {
+    for (element, count) in from {
+        *into.entry(element).or_insert(0) += count * multiplier;
+    }
+}
+
+/// Looks up one entry's weight from `elements.csv`/`absolute.csv` (depending
+/// on `--average`), or `None` if `symbol` isn't a known element.
+fn lookup_element_weight(symbol: &str, is_average: bool) -> Option<f32> {
+    let current_exe_res = env::current_exe();
+    let mut current_exe_path: PathBuf = current_exe_res.expect("Could not read executable path.");
+    current_exe_path.pop();
+
+    let elements_csv_path: PathBuf = if is_average {
+        current_exe_path.join("../../data/elements.csv")
+    } else {
+        current_exe_path.join("../../data/absolute.csv")
+    }
+    .canonicalize()
+    .expect("Canonicalization of executable path failed.");
+
+    let elements_csv_stream = csv::Reader::from_path(elements_csv_path);
+    let mut elements_deserialize_binding = elements_csv_stream.unwrap();
+    let mut elements_csv_deserialized = elements_deserialize_binding.deserialize::<ElementRow>();
+
+    elements_csv_deserialized
+        .find(|row| row.as_ref().expect("Could not get ElementRow").element == symbol)
+        .map(|row| {
+            row.expect("Could not get ElementRow")
+                .weight
                 .parse::<f32>()
-                .expect("Could not parse element count into a float32.");
-            aggregate_mass += element_weight * element_count;
+                .expect("Could not parse element mass from dataset as a float32.")
+        })
+}
+
+/// Looks up `symbol` in `data/abbreviations.csv`, returning the formula it
+/// expands to, or `None` if `symbol` isn't a known abbreviation.
+fn lookup_abbreviation_formula(symbol: &str) -> Option<String> {
+    let current_exe_res = env::current_exe();
+    let mut current_exe_path: PathBuf = current_exe_res.expect("Could not read executable path.");
+    current_exe_path.pop();
+
+    let abbr_csv_path: PathBuf = current_exe_path
+        .join("../../data/abbreviations.csv")
+        .canonicalize()
+        .expect("Canonicalization of executable path failed.");
+    let abbr_csv_stream = csv::Reader::from_path(abbr_csv_path);
+    let mut abbr_deserialize_binding = abbr_csv_stream.unwrap();
+    let mut abbr_csv_deserialized = abbr_deserialize_binding.deserialize::<AbbreviationRow>();
+
+    abbr_csv_deserialized
+        .find(|row| row.as_ref().expect("Could not get AbbreviationRow").abbreviation == symbol)
+        .map(|row| row.expect("Could not get AbbreviationRow").formula)
+}
+
+/// Sums the mass of a composition map, expanding any key that isn't a known
+/// element (e.g. `Ph`, `Ac`) as an abbreviation from `data/abbreviations.csv`.
+/// Returns `Err` instead of panicking when a key is neither, so batch callers
+/// like `process_csv_file` can skip a bad row instead of aborting.
+fn mass_from_formula(composition: &Composition, is_debug: bool, is_average: bool) -> Result<f32, String> {
+    let mut aggregate_mass: f32 = 0.0;
+
+    for (symbol, &count) in composition {
+        if let Some(weight) = lookup_element_weight(symbol, is_average) {
+            if is_debug {
+                println!("Element: {:?}  Count: {:?}", symbol, count);
+            }
+            aggregate_mass += weight * count as f32;
+            continue;
         }
+
+        if let Some(formula) = lookup_abbreviation_formula(symbol) {
+            let (abbr_composition, _charge) = parse_molecular_formula(&formula)?;
+            if is_debug {
+                println!("Expanded Abbreviation {:?}: {:?}", symbol, abbr_composition);
+            }
+            aggregate_mass += mass_from_formula(&abbr_composition, is_debug, is_average)? * count as f32;
+            continue;
+        }
+
+        return Err(format!("Unknown element or abbreviation {:?} in formula.", symbol));
     }
-    aggregate_mass
+
+    if is_debug {
+        println!("Aggregated Mass: {:?}", aggregate_mass);
+    }
+
+    Ok(aggregate_mass)
 }
 
-/// Gives deprotonated and protonated (M+2).
-fn parse_protein_formula<'a>(formula: String) -> Vec<String> {
-    let reg = Regex::new(r"[Aa]|[C-Yc-y]").expect("RegEx parsing error.");
-    let binding = reg.to_owned();
-    let out = binding.find_iter(formula.as_str());
+/// Whether `symbol` is a known element in `absolute.csv` (the element set is
+/// the same in both the exact and average tables, so either works here).
+fn element_exists(symbol: &str) -> bool {
+    lookup_element_weight(symbol, false).is_some()
+}
+
+/// Expands a composition map into pure elemental composition, recursively
+/// resolving any abbreviation keys (e.g. `Ph`, `Ac`) down to the elements
+/// they're built from, so `--isotopes` can look every key up in
+/// `data/isotopes.csv`. Returns `Err` instead of panicking on an unknown key,
+/// for the same reason `mass_from_formula` does.
+fn expand_composition(composition: &Composition) -> Result<Composition, String> {
+    let mut expanded: Composition = HashMap::new();
+
+    for (symbol, &count) in composition {
+        if element_exists(symbol) {
+            *expanded.entry(symbol.clone()).or_insert(0) += count;
+            continue;
+        }
+
+        if let Some(formula) = lookup_abbreviation_formula(symbol) {
+            let (abbr_composition, _charge) = parse_molecular_formula(&formula)?;
+            let abbr_expanded = expand_composition(&abbr_composition)?;
+            merge_composition(&mut expanded, abbr_expanded, count);
+            continue;
+        }
+
+        return Err(format!("Unknown element or abbreviation {:?} in formula.", symbol));
+    }
+
+    Ok(expanded)
+}
 
+static MODIFICATION_TABLE: OnceLock<HashMap<String, ModificationEntry>> = OnceLock::new();
+
+/// The PTM ontology, compiled from `data/modifications.csv` into
+/// `OUT_DIR/modifications.bin` by `build.rs` and deserialized once per
+/// process, so residue-by-residue lookups don't re-parse CSV like the
+/// element/amino-acid lookups above do.
+fn modification_table() -> &'static HashMap<String, ModificationEntry> {
+    MODIFICATION_TABLE.get_or_init(|| {
+        let bytes = include_bytes!(concat!(env!("OUT_DIR"), "/modifications.bin"));
+        bincode::deserialize(bytes).expect("Could not deserialize compiled-in modification ontology.")
+    })
+}
+
+/// N-/C-terminal tags recognized in `AC-PEPTIDE-NH2`-style input, mapped to
+/// the modification in `data/modifications.csv` that they apply.
+const TERMINAL_MODIFICATION_ALIASES: &[(&str, &str)] = &[("AC", "Acetyl"), ("NH2", "Amidation")];
+
+fn resolve_terminal_alias(tag: &str) -> Option<&'static str> {
+    TERMINAL_MODIFICATION_ALIASES
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(tag))
+        .map(|(_, name)| *name)
+}
+
+/// Looks up a single amino acid's formula from `amino.csv` by its one-letter
+/// code (matches `[Aa]|[C-Yc-y]`, i.e. every IUPAC letter except the
+/// ambiguous `B`/`Z` codes).
+fn residue_formula(letter: char) -> String {
     let current_exe_res = env::current_exe();
     let mut current_exe_path: PathBuf = current_exe_res.expect("Could not read executable path.");
     current_exe_path.pop();
 
-    let mut out_vec: Vec<String> = vec![];
-    out.for_each(|val| {
-        let aa_csv_path: PathBuf = current_exe_path
-            .join("../../data/amino.csv")
-            .canonicalize()
-            .expect("Canonicalization of executable path failed.");
-        let aa_csv_stream = csv::Reader::from_path(aa_csv_path);
-        let aa_deserialize_binding = aa_csv_stream.unwrap();
-        let mut aa_csv_deserialized = aa_deserialize_binding.into_deserialize::<AminoAcidRow>();
-
-        let aa_formula = aa_csv_deserialized
-            .find(|aa_row| aa_row.as_ref().unwrap().letter == val.as_str().to_owned());
-        let in_vec: Vec<String> = parse_molecular_formula(aa_formula.unwrap().unwrap().formula);
-
-        out_vec.append(in_vec.to_vec().as_mut());
-    });
-    out_vec
+    let aa_csv_path: PathBuf = current_exe_path
+        .join("../../data/amino.csv")
+        .canonicalize()
+        .expect("Canonicalization of executable path failed.");
+    let aa_csv_stream = csv::Reader::from_path(aa_csv_path);
+    let aa_deserialize_binding = aa_csv_stream.unwrap();
+    let mut aa_csv_deserialized = aa_deserialize_binding.into_deserialize::<AminoAcidRow>();
+
+    let aa_formula = aa_csv_deserialized.find(|aa_row| aa_row.as_ref().unwrap().letter == letter.to_string());
+    aa_formula.unwrap().unwrap().formula
+}
+
+/// Looks up `name` in the compiled-in modification ontology and applies its
+/// delta: a formula-based modification's composition is parsed and merged
+/// into `composition`, while a numeric-delta modification (mono or average,
+/// per `is_average`) is folded straight into `numeric_delta`.
+fn apply_modification(name: &str, is_average: bool, composition: &mut Composition, numeric_delta: &mut f32) {
+    let entry = modification_table()
+        .get(name)
+        .unwrap_or_else(|| panic!("Unknown modification {:?}. Check data/modifications.csv.", name));
+
+    if let Some(formula) = &entry.formula {
+        let (mod_composition, _charge) =
+            parse_molecular_formula(formula).unwrap_or_else(|reason| panic!("{}", reason));
+        merge_composition(composition, mod_composition, 1);
+        return;
+    }
+
+    let delta = if is_average { entry.avg_delta } else { entry.mono_delta };
+    *numeric_delta += delta
+        .unwrap_or_else(|| panic!("Modification {:?} has neither a formula nor a numeric delta.", name))
+        as f32;
+}
+
+/// Splits `AC-PEPTIDE-NH2`-style input into its optional N-terminal tag, the
+/// core residue sequence, and its optional C-terminal tag.
+fn split_terminal_segments(formula: &str) -> (Option<&str>, String, Option<&str>) {
+    let segments: Vec<&str> = formula.split('-').collect();
+    match segments.as_slice() {
+        [only] => (None, only.to_string(), None),
+        [first, second] if resolve_terminal_alias(first).is_some() => (Some(*first), second.to_string(), None),
+        [first, second] if resolve_terminal_alias(second).is_some() => (None, first.to_string(), Some(*second)),
+        [first, middle @ .., last] if !middle.is_empty() => (Some(*first), middle.join("-"), Some(*last)),
+        _ => (None, segments.join(""), None),
+    }
 }
 
-fn parse_nucleic_formula<'a>(formula: String, is_rna: bool, is_average: bool) -> Vec<String> {
+/// One core residue: its bare composition plus the name of any
+/// `[Modification]` immediately following it.
+struct ResidueToken {
+    composition: Composition,
+    modification: Option<String>,
+}
+
+/// Scans a core peptide sequence (no terminal tags) into its residues,
+/// matching `[Aa]|[C-Yc-y]` the same way the original regex-only parser did,
+/// and pairing each residue with a trailing `[Name]` modification if present.
+fn parse_core_residues(core: &str) -> Vec<ResidueToken> {
+    let residue_reg = Regex::new(r"[Aa]|[C-Yc-y]").expect("RegEx parsing error.");
+    let core_chars: Vec<char> = core.chars().collect();
+
+    let mut residues: Vec<ResidueToken> = vec![];
+    let mut index = 0;
+    while index < core_chars.len() {
+        let letter = core_chars[index];
+        index += 1;
+        if !residue_reg.is_match(&letter.to_string()) {
+            continue;
+        }
+
+        let (composition, _charge) = parse_molecular_formula(&residue_formula(letter))
+            .unwrap_or_else(|reason| panic!("{}", reason));
+        let mut modification = None;
+
+        if core_chars.get(index) == Some(&'[') {
+            let close = core_chars[index..]
+                .iter()
+                .position(|&c| c == ']')
+                .expect("Unterminated \"[...]\" modification in peptide.");
+            modification = Some(core_chars[index + 1..index + close].iter().collect());
+            index += close + 1;
+        }
+
+        residues.push(ResidueToken { composition, modification });
+    }
+    residues
+}
+
+/// Parses a peptide, e.g. `PEPTIDE`, `PEPT[Phospho]IDE`, or
+/// `AC-PEPTIDE-NH2`, into its summed residue formula plus any modification
+/// deltas. `[Name]` immediately after a residue applies that modification to
+/// the residue; an `N-term-C-term` wrapping applies N-/C-terminal
+/// modifications (see `TERMINAL_MODIFICATION_ALIASES`).
+fn parse_protein_formula(formula: &str, is_average: bool) -> ParsedSequence {
+    let (n_term, core, c_term) = split_terminal_segments(formula);
+
+    let mut composition: Composition = HashMap::new();
+    let mut numeric_delta: f32 = 0.0;
+    let mut residue_count: i64 = 0;
+
+    if let Some(name) = n_term.and_then(resolve_terminal_alias) {
+        apply_modification(name, is_average, &mut composition, &mut numeric_delta);
+    }
+
+    for residue in parse_core_residues(&core) {
+        residue_count += 1;
+        merge_composition(&mut composition, residue.composition, 1);
+
+        if let Some(name) = residue.modification {
+            apply_modification(&name, is_average, &mut composition, &mut numeric_delta);
+        }
+    }
+
+    if let Some(name) = c_term.and_then(resolve_terminal_alias) {
+        apply_modification(name, is_average, &mut composition, &mut numeric_delta);
+    }
+
+    // One water is lost per peptide bond, i.e. for every residue after the first.
+    numeric_delta -= (residue_count - 1).max(0) as f32 * WATER_MASS;
+
+    ParsedSequence { composition, numeric_delta, charge: None }
+}
+
+/// Per-residue masses of a peptide's core sequence (each including its own
+/// bracketed modification, if any), plus the N-/C-terminal modification
+/// deltas — the decomposition `--fragments` needs to build up b/y (and
+/// a/c/x/z) ion ladders residue by residue instead of one aggregate mass.
+fn peptide_residue_masses(formula: &str, is_average: bool, is_debug: bool) -> (Vec<f32>, f32, f32) {
+    let (n_term, core, c_term) = split_terminal_segments(formula);
+
+    let mut n_term_delta: f32 = 0.0;
+    if let Some(name) = n_term.and_then(resolve_terminal_alias) {
+        let mut composition = HashMap::new();
+        apply_modification(name, is_average, &mut composition, &mut n_term_delta);
+        n_term_delta +=
+            mass_from_formula(&composition, is_debug, is_average).unwrap_or_else(|reason| panic!("{}", reason));
+    }
+
+    let mut c_term_delta: f32 = 0.0;
+    if let Some(name) = c_term.and_then(resolve_terminal_alias) {
+        let mut composition = HashMap::new();
+        apply_modification(name, is_average, &mut composition, &mut c_term_delta);
+        c_term_delta +=
+            mass_from_formula(&composition, is_debug, is_average).unwrap_or_else(|reason| panic!("{}", reason));
+    }
+
+    // Each residue's bare formula is a free amino acid (per amino.csv); the
+    // fragment-ion formulas below (`fragment_series_mass`) expect residue
+    // masses with one water already removed, matching the precursor's
+    // `parse_protein_formula`, which adds that water back exactly once for
+    // the whole peptide.
+    let residue_masses = parse_core_residues(&core)
+        .into_iter()
+        .map(|residue| {
+            let mut composition = residue.composition;
+            let mut delta: f32 = 0.0;
+            if let Some(name) = residue.modification {
+                apply_modification(&name, is_average, &mut composition, &mut delta);
+            }
+            mass_from_formula(&composition, is_debug, is_average).unwrap_or_else(|reason| panic!("{}", reason))
+                + delta
+                - WATER_MASS
+        })
+        .collect();
+
+    (residue_masses, n_term_delta, c_term_delta)
+}
+
+/// The singly-charged ion mass for one fragment ion series, given the
+/// cumulative residue mass from the relevant terminus and that terminus's
+/// modification delta: `b = residues + proton`, `y = residues + water +
+/// proton` (per-spec), with `a/c` and `x/z` offset from `b`/`y` by the
+/// standard CO/NH3 neutral losses.
+fn fragment_series_mass(
+    series: &str,
+    residue_sum: f32,
+    terminal_delta: f32,
+    h_mass: f32,
+    water_mass: f32,
+    nh3_mass: f32,
+    co_mass: f32,
+) -> f32 {
+    match series {
+        "b" => terminal_delta + residue_sum + h_mass,
+        "a" => terminal_delta + residue_sum + h_mass - co_mass,
+        "c" => terminal_delta + residue_sum + h_mass + nh3_mass,
+        "y" => terminal_delta + residue_sum + water_mass + h_mass,
+        "x" => terminal_delta + residue_sum + water_mass + h_mass + co_mass - 2.0 * h_mass,
+        "z" => terminal_delta + residue_sum + water_mass + h_mass - nh3_mass,
+        other => panic!("Unknown ion series {:?}. Supported: a, b, c, x, y, z.", other),
+    }
+}
+
+/// Prints the requested fragment-ion ladder (the series listed via `--ions`,
+/// or b/y when `--ions` is not given) for a peptide, at the given charge.
+fn print_fragment_ions(formula: &str, is_average: bool, is_debug: bool, charge: i32, ion_series: &[String]) {
+    let (residue_masses, n_term_delta, c_term_delta) = peptide_residue_masses(formula, is_average, is_debug);
+    let h_mass = HYDROGEN_MASS;
+    let water_mass = WATER_MASS;
+    let nh3_mass = NITROGEN_MASS + 3.0 * h_mass;
+    let co_mass = CARBON_MASS + OXYGEN_MASS;
+
+    println!("{:>6} {:>5} {:>14}", "Ion", "Pos", "m/z");
+
+    // The full-length (N of N) prefix/suffix is the intact precursor, not a
+    // backbone fragment, so both ladders stop one residue short of the end.
+    let fragment_count = residue_masses.len().saturating_sub(1);
+
+    let mut prefix_sum: f32 = 0.0;
+    for (position, &mass) in residue_masses.iter().take(fragment_count).enumerate() {
+        prefix_sum += mass;
+        for series in ion_series {
+            if matches!(series.as_str(), "a" | "b" | "c") {
+                let base = fragment_series_mass(series, prefix_sum, n_term_delta, h_mass, water_mass, nh3_mass, co_mass);
+                let mz = (base + (charge - 1) as f32 * h_mass) / charge as f32;
+                println!("{:>6} {:>5} {:>14.4}", format!("{}{}", series, position + 1), position + 1, mz);
+            }
+        }
+    }
+
+    let mut suffix_sum: f32 = 0.0;
+    for (offset, &mass) in residue_masses.iter().rev().take(fragment_count).enumerate() {
+        suffix_sum += mass;
+        let position = offset + 1;
+        for series in ion_series {
+            if matches!(series.as_str(), "x" | "y" | "z") {
+                let base = fragment_series_mass(series, suffix_sum, c_term_delta, h_mass, water_mass, nh3_mass, co_mass);
+                let mz = (base + (charge - 1) as f32 * h_mass) / charge as f32;
+                println!("{:>6} {:>5} {:>14.4}", format!("{}{}", series, position), position, mz);
+            }
+        }
+    }
+}
+
+fn parse_nucleic_formula(formula: &str, is_rna: bool, is_average: bool) -> Composition {
     let reg = if is_rna {
         Regex::new(r"[AUGCaugc]")
     } else {
@@ -315,13 +1064,13 @@ fn parse_nucleic_formula<'a>(formula: String, is_rna: bool, is_average: bool) ->
     .expect("RegEx parsing error.");
 
     let binding = reg.to_owned();
-    let out = binding.find_iter(formula.as_str());
+    let out = binding.find_iter(formula);
 
     let current_exe_res = env::current_exe();
     let mut current_exe_path: PathBuf = current_exe_res.expect("Could not read executable path.");
     current_exe_path.pop();
 
-    let mut out_vec: Vec<String> = vec![];
+    let mut composition: Composition = HashMap::new();
     out.for_each(|val| {
         let na_csv_path: PathBuf = if is_average {
             current_exe_path.join("../../data/nucleic.csv")
@@ -338,9 +1087,292 @@ fn parse_nucleic_formula<'a>(formula: String, is_rna: bool, is_average: bool) ->
         let na_formula = na_csv_deserialized.find(|na_row| {
             na_row.as_ref().unwrap().letter == val.as_str().to_owned().to_uppercase()
         });
-        let in_vec: Vec<String> = parse_molecular_formula(na_formula.unwrap().unwrap().formula);
+        let (residue_composition, _charge) = parse_molecular_formula(&na_formula.unwrap().unwrap().formula)
+            .unwrap_or_else(|reason| panic!("{}", reason));
 
-        out_vec.append(in_vec.to_vec().as_mut());
+        merge_composition(&mut composition, residue_composition, 1);
     });
-    out_vec
+    composition
+}
+
+/// Shifts every peak in a distribution by a flat mass `delta` (e.g. a
+/// numeric-delta modification, or the peptide-bond water-loss correction)
+/// without touching the relative intensities.
+fn shift_distribution_mass(distribution: IsotopeDistribution, delta: f64) -> IsotopeDistribution {
+    distribution
+        .into_iter()
+        .map(|(mass, probability)| (mass + delta, probability))
+        .collect()
+}
+
+/// A theoretical isotope distribution: sorted (mass, probability) peaks.
+type IsotopeDistribution = Vec<(f64, f64)>;
+
+/// Reads every isotope of `element` from `data/isotopes.csv` as a
+/// single-atom distribution.
+fn load_element_isotopes(element: &str) -> IsotopeDistribution {
+    let current_exe_res = env::current_exe();
+    let mut current_exe_path: PathBuf = current_exe_res.expect("Could not read executable path.");
+    current_exe_path.pop();
+
+    let isotopes_csv_path: PathBuf = current_exe_path
+        .join("../../data/isotopes.csv")
+        .canonicalize()
+        .expect("Canonicalization of executable path failed.");
+    let isotopes_csv_stream = csv::Reader::from_path(isotopes_csv_path);
+    let mut isotopes_deserialize_binding = isotopes_csv_stream.unwrap();
+    let isotopes_csv_deserialized = isotopes_deserialize_binding.deserialize::<IsotopeRow>();
+
+    let distribution: IsotopeDistribution = isotopes_csv_deserialized
+        .filter_map(|row| row.ok())
+        .filter(|row| row.element == element)
+        .map(|row| {
+            let mass = row
+                .isotope_mass
+                .parse::<f64>()
+                .expect("Could not parse isotope mass as an f64.");
+            let abundance = row
+                .abundance
+                .parse::<f64>()
+                .expect("Could not parse isotope abundance as an f64.");
+            (mass, abundance)
+        })
+        .collect();
+
+    if distribution.is_empty() {
+        panic!("No isotope data for element {:?} in data/isotopes.csv.", element);
+    }
+
+    distribution
+}
+
+/// Convolves two distributions: every pair of peaks combines its masses
+/// (added) and probabilities (multiplied), then the result is pruned and
+/// centroided so it doesn't grow combinatorially.
+fn convolve(a: &IsotopeDistribution, b: &IsotopeDistribution) -> IsotopeDistribution {
+    let mut raw: IsotopeDistribution = Vec::with_capacity(a.len() * b.len());
+    for &(mass_a, prob_a) in a {
+        for &(mass_b, prob_b) in b {
+            raw.push((mass_a + mass_b, prob_a * prob_b));
+        }
+    }
+    prune_and_merge(raw)
+}
+
+/// Drops peaks below `ISOTOPE_PRUNE_THRESHOLD` of the running maximum, then
+/// intensity-weight-averages ("centroids") any peaks within
+/// `ISOTOPE_MERGE_TOLERANCE` Da of each other.
+fn prune_and_merge(mut distribution: IsotopeDistribution) -> IsotopeDistribution {
+    distribution.sort_by(|a, b| a.0.partial_cmp(&b.0).expect("NaN mass in distribution."));
+
+    let max_probability = distribution
+        .iter()
+        .fold(0.0_f64, |max, &(_, probability)| max.max(probability));
+    let threshold = max_probability * ISOTOPE_PRUNE_THRESHOLD;
+
+    let mut merged: IsotopeDistribution = Vec::new();
+    for (mass, probability) in distribution {
+        if probability < threshold {
+            continue;
+        }
+
+        if let Some(last) = merged.last_mut() {
+            if (mass - last.0).abs() <= ISOTOPE_MERGE_TOLERANCE {
+                let total_probability = last.1 + probability;
+                last.0 = (last.0 * last.1 + mass * probability) / total_probability;
+                last.1 = total_probability;
+                continue;
+            }
+        }
+
+        merged.push((mass, probability));
+    }
+
+    merged
+}
+
+/// Self-convolves a single element's isotope distribution `count` times
+/// using square-and-multiply, so an element with a large count only takes
+/// O(log count) convolutions instead of `count - 1`.
+fn element_distribution_pow(element: &str, count: i64) -> IsotopeDistribution {
+    if count <= 0 {
+        return vec![(0.0, 1.0)];
+    }
+
+    let mut power = load_element_isotopes(element);
+    let mut result: Option<IsotopeDistribution> = None;
+    let mut remaining = count;
+
+    while remaining > 0 {
+        if remaining & 1 == 1 {
+            result = Some(match result {
+                Some(acc) => convolve(&acc, &power),
+                None => power.clone(),
+            });
+        }
+        remaining >>= 1;
+        if remaining > 0 {
+            power = convolve(&power, &power);
+        }
+    }
+
+    result.expect("count > 0 guarantees at least one convolution.")
+}
+
+/// Combines every element's isotope distribution into the full molecular
+/// isotope envelope.
+fn isotope_distribution(composition: &HashMap<String, i64>) -> IsotopeDistribution {
+    let mut total: Option<IsotopeDistribution> = None;
+
+    for (element, &count) in composition {
+        let element_dist = element_distribution_pow(element, count);
+        total = Some(match total {
+            Some(acc) => convolve(&acc, &element_dist),
+            None => element_dist,
+        });
+    }
+
+    total.unwrap_or_else(|| vec![(0.0, 1.0)])
+}
+
+/// Normalizes a distribution so the base peak is 100% and prints every peak
+/// at or above `min_intensity` (a fraction of the base peak, `--min-intensity`,
+/// defaulting to `ISOTOPE_DISPLAY_CUTOFF`), most intense first.
+fn print_isotope_distribution(distribution: &IsotopeDistribution, min_intensity: f64) {
+    let max_probability = distribution
+        .iter()
+        .fold(0.0_f64, |max, &(_, probability)| max.max(probability));
+
+    let mut peaks: Vec<(f64, f64)> = distribution
+        .iter()
+        .copied()
+        .filter(|&(_, probability)| probability >= max_probability * min_intensity)
+        .collect();
+    peaks.sort_by(|a, b| b.1.partial_cmp(&a.1).expect("NaN probability in distribution."));
+
+    println!("{:>14} {:>12}", "Mass (Da)", "Rel. Int.");
+    for (mass, probability) in peaks {
+        println!("{:>14.4} {:>11.2}%", mass, (probability / max_probability) * 100.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64, tol: f64) -> bool {
+        (a - b).abs() <= tol
+    }
+
+    #[test]
+    fn convolve_adds_masses_and_multiplies_probabilities() {
+        let a = vec![(0.0, 0.5), (1.0, 0.5)];
+        let b = vec![(10.0, 0.25), (12.0, 0.75)];
+        let result = convolve(&a, &b);
+
+        assert_eq!(result.len(), 4);
+        let total_probability: f64 = result.iter().map(|&(_, probability)| probability).sum();
+        assert!(close(total_probability, 1.0, 1e-9));
+
+        let peak_10 = result.iter().find(|&&(mass, _)| close(mass, 10.0, 1e-9)).unwrap();
+        assert!(close(peak_10.1, 0.5 * 0.25, 1e-9));
+    }
+
+    #[test]
+    fn convolve_prunes_low_intensity_peaks() {
+        let a = vec![(0.0, 1.0)];
+        let b = vec![(0.0, 1.0 - 1e-9), (5.0, 1e-9)];
+        let result = convolve(&a, &b);
+
+        // The 5.0 peak is far below ISOTOPE_PRUNE_THRESHOLD of the base peak
+        // and should be dropped rather than carried through.
+        assert_eq!(result.len(), 1);
+        assert!(close(result[0].0, 0.0, 1e-9));
+    }
+
+    #[test]
+    fn convolve_merges_close_peaks() {
+        let a = vec![(0.0, 0.5), (ISOTOPE_MERGE_TOLERANCE / 2.0, 0.5)];
+        let b = vec![(0.0, 1.0)];
+        let result = convolve(&a, &b);
+
+        // Both input peaks are within ISOTOPE_MERGE_TOLERANCE of each other,
+        // so they should centroid into one.
+        assert_eq!(result.len(), 1);
+        assert!(close(result[0].1, 1.0, 1e-9));
+    }
+
+    #[test]
+    fn element_distribution_pow_zero_or_negative_count_is_the_empty_molecule() {
+        // count <= 0 is a pure short-circuit that never reads
+        // data/isotopes.csv, so it's the only branch of this function
+        // exercisable without that file on disk next to the executable
+        // (`load_element_isotopes` reads it at run time); the count > 0
+        // path (the actual square-and-multiply recursion) has no unit test
+        // here and is only exercised by running `--isotopes`.
+        assert_eq!(element_distribution_pow("C", 0), vec![(0.0, 1.0)]);
+        assert_eq!(element_distribution_pow("C", -3), vec![(0.0, 1.0)]);
+    }
+
+    #[test]
+    fn fragment_series_mass_offsets() {
+        let residue_sum = 300.0_f32;
+        let terminal_delta = 0.0_f32;
+        let h_mass = HYDROGEN_MASS;
+        let water_mass = WATER_MASS;
+        let nh3_mass = NITROGEN_MASS + 3.0 * h_mass;
+        let co_mass = CARBON_MASS + OXYGEN_MASS;
+
+        let b = fragment_series_mass("b", residue_sum, terminal_delta, h_mass, water_mass, nh3_mass, co_mass);
+        let a = fragment_series_mass("a", residue_sum, terminal_delta, h_mass, water_mass, nh3_mass, co_mass);
+        let c = fragment_series_mass("c", residue_sum, terminal_delta, h_mass, water_mass, nh3_mass, co_mass);
+        let y = fragment_series_mass("y", residue_sum, terminal_delta, h_mass, water_mass, nh3_mass, co_mass);
+        let x = fragment_series_mass("x", residue_sum, terminal_delta, h_mass, water_mass, nh3_mass, co_mass);
+        let z = fragment_series_mass("z", residue_sum, terminal_delta, h_mass, water_mass, nh3_mass, co_mass);
+
+        assert!((b - (residue_sum + h_mass)).abs() < 1e-6);
+        assert!((a - (b - co_mass)).abs() < 1e-6);
+        assert!((c - (b + nh3_mass)).abs() < 1e-6);
+        assert!((y - (residue_sum + water_mass + h_mass)).abs() < 1e-6);
+        assert!((x - (y + co_mass - 2.0 * h_mass)).abs() < 1e-6);
+        assert!((z - (y - nh3_mass)).abs() < 1e-6);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown ion series")]
+    fn fragment_series_mass_rejects_unknown_series() {
+        fragment_series_mass("q", 0.0, 0.0, HYDROGEN_MASS, WATER_MASS, 0.0, 0.0);
+    }
+
+    #[test]
+    fn parse_molecular_formula_nested_group() {
+        let (composition, charge) = parse_molecular_formula("Ca(OH)2").unwrap();
+        assert_eq!(composition.get("Ca"), Some(&1));
+        assert_eq!(composition.get("O"), Some(&2));
+        assert_eq!(composition.get("H"), Some(&2));
+        assert_eq!(charge, None);
+    }
+
+    #[test]
+    fn parse_molecular_formula_hydrate() {
+        let (composition, charge) = parse_molecular_formula("CuSO4\u{b7}5H2O").unwrap();
+        assert_eq!(composition.get("Cu"), Some(&1));
+        assert_eq!(composition.get("S"), Some(&1));
+        assert_eq!(composition.get("O"), Some(&9));
+        assert_eq!(composition.get("H"), Some(&10));
+        assert_eq!(charge, None);
+    }
+
+    #[test]
+    fn parse_molecular_formula_charge() {
+        let (composition, charge) = parse_molecular_formula("SO4^2-").unwrap();
+        assert_eq!(composition.get("S"), Some(&1));
+        assert_eq!(composition.get("O"), Some(&4));
+        assert_eq!(charge, Some(-2));
+    }
+
+    #[test]
+    fn parse_molecular_formula_reports_unbalanced_parens_instead_of_panicking() {
+        assert!(parse_molecular_formula("Ca(OH2").is_err());
+    }
 }
@@ -0,0 +1,16 @@
+//! Shared between `main.rs` and `build.rs` (included there via `include!`)
+//! so the CSV ontology row, the compiled table's value type, and the bincode
+//! schema all stay in lockstep.
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ModificationEntry {
+    pub name: String,
+    /// A formula-based delta (e.g. "H1P1O3" for Phospho), fed through
+    /// `parse_molecular_formula`. Mutually exclusive with the numeric deltas.
+    pub formula: Option<String>,
+    /// A direct monoisotopic mass delta, used when `formula` can't express
+    /// the modification (e.g. a substitution rather than an addition).
+    pub mono_delta: Option<f64>,
+    /// The average-mass equivalent of `mono_delta`, used when `--average` is set.
+    pub avg_delta: Option<f64>,
+}